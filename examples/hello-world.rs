@@ -83,7 +83,12 @@ impl MyApp {
         let app = Self::default();
 
         if let Some(ref wgpu) = cc.wgpu_render_state {
-            GlyphonRenderer::insert(wgpu, Arc::clone(&app.font_system));
+            GlyphonRenderer::insert(
+                wgpu,
+                Arc::clone(&app.font_system),
+                None,
+                egui_glyphon::GlyphonRendererConfig::default(),
+            );
         }
 
         app
@@ -105,11 +110,12 @@ impl eframe::App for MyApp {
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.add(Slider::new(&mut self.size, 0.1..=67.5));
             let rect = Rect::from_min_size(ui.cursor().min, size);
-            let buffers: Vec<BufferWithTextArea<Buffer>> = vec![BufferWithTextArea::new(
+            let buffers: Vec<BufferWithTextArea> = vec![BufferWithTextArea::new(
                 Arc::clone(&self.buffer),
                 rect,
                 1.0,
                 egui_glyphon::glyphon::Color::rgb(255, 255, 255),
+                Vec::new(),
                 ui.ctx(),
             )];
             ui.painter().add(egui_wgpu::Callback::new_paint_callback(
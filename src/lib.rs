@@ -1,6 +1,7 @@
 //! This crate is for using [`glyphon`] to render advanced shaped text to the screen in an [`egui`] application
 //! Please see the example for a primer on how to use this crate
 use std::ops::DerefMut;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use egui::mutex::{Mutex, RwLock};
@@ -8,8 +9,9 @@ use egui::{Pos2, Rect, Vec2};
 use egui_wgpu::wgpu;
 use egui_wgpu::ScreenDescriptor;
 use glyphon::{
-    Buffer, Color, ColorMode, FontSystem, PrepareError, RenderError, Resolution, SwashCache,
-    TextArea, TextAtlas, TextBounds, TextRenderer, Viewport
+    Buffer, Color, ColorMode, CustomGlyph, FontSystem, PrepareError, RasterizeCustomGlyphRequest,
+    RasterizedCustomGlyph, RenderError, Resolution, SwashCache, TextArea, TextAtlas, TextBounds,
+    TextRenderer, Viewport,
 };
 
 pub use glyphon;
@@ -21,6 +23,9 @@ pub struct BufferWithTextArea {
     pub scale: f32,
     pub opacity: f32,
     pub default_color: Color,
+    /// Inline glyphs (e.g. rasterized SVG icons) to be drawn alongside the shaped text in
+    /// [`BufferWithTextArea::buffer`]. Forwarded verbatim to [`glyphon::TextArea::custom_glyphs`].
+    pub custom_glyphs: Vec<CustomGlyph>,
 }
 
 /// Use this function to find out the dimensions of a buffer, translate the resulting rect and use it in [`BufferWithTextArea::new`]
@@ -54,6 +59,7 @@ impl BufferWithTextArea {
         rect: Rect,
         opacity: f32,
         default_color: Color,
+        custom_glyphs: Vec<CustomGlyph>,
         ctx: &egui::Context,
     ) -> Self {
         let ppi = ctx.pixels_per_point();
@@ -64,6 +70,39 @@ impl BufferWithTextArea {
             scale: ppi,
             opacity,
             default_color,
+            custom_glyphs,
+        }
+    }
+}
+
+/// Configuration for [`GlyphonRenderer::insert`].
+///
+/// The defaults reproduce this crate's previous hardcoded behavior: sRGB blending
+/// (`ColorMode::Web`) targeting the surface's own format with no multisampling.
+pub struct GlyphonRendererConfig {
+    /// Passed to [`glyphon::TextAtlas::with_color_mode`]. Use [`ColorMode::Accurate`] when
+    /// rendering into a linear-space (`Accurate`) egui surface instead of an sRGB one.
+    pub color_mode: ColorMode,
+    /// The format the atlas renders into. Defaults to the render state's own
+    /// `target_format` when left as `None`.
+    pub target_format: Option<wgpu::TextureFormat>,
+    /// Passed to [`glyphon::TextRenderer::new`]; must match the sample count of the render
+    /// pass this renderer is used in.
+    pub multisample: wgpu::MultisampleState,
+    /// Called with the error glyphon returned when text preparation still fails after the
+    /// atlas-full retry (see [`GlyphonRendererCallback::prepare`]). Left as `None`, failures are
+    /// silent and that frame's text is simply skipped; set this to route them into the host
+    /// app's own logging instead of this crate printing to stderr unconditionally.
+    pub on_prepare_error: Option<Box<dyn Fn(PrepareError) + Send + Sync>>,
+}
+
+impl Default for GlyphonRendererConfig {
+    fn default() -> Self {
+        GlyphonRendererConfig {
+            color_mode: ColorMode::Web,
+            target_format: None,
+            multisample: wgpu::MultisampleState::default(),
+            on_prepare_error: None,
         }
     }
 }
@@ -75,11 +114,41 @@ pub struct GlyphonRenderer {
     atlas: TextAtlas,
     viewport: Viewport,
     text_renderer: TextRenderer,
+    multisample: wgpu::MultisampleState,
+    /// The resolution last uploaded to `viewport`, so successive frames at the same resolution
+    /// don't each re-upload it.
+    last_resolution: Option<Resolution>,
+    /// Set by [`GlyphonRendererCallback::prepare`] and cleared by [`GlyphonRendererCallback::paint`].
+    /// The single `text_renderer` below can only hold one callback's prepared glyphs at a time,
+    /// so if this is still set when another callback's `prepare` comes in, an earlier callback's
+    /// glyphs haven't been painted yet and are about to be overwritten.
+    callback_in_flight: AtomicBool,
+    /// Whether this frame's `prepare` (including its atlas-full retry) actually produced glyphs
+    /// to render. `paint` checks this before calling `render`, so a frame where preparation kept
+    /// failing skips rendering instead of unwrapping into a panic.
+    has_prepared_state: AtomicBool,
+    rasterize_custom_glyph:
+        Option<Box<dyn Fn(RasterizeCustomGlyphRequest) -> Option<RasterizedCustomGlyph> + Send + Sync>>,
+    on_prepare_error: Option<Box<dyn Fn(PrepareError) + Send + Sync>>,
 }
 
 impl GlyphonRenderer {
-    /// Insert an instance of itself into the [`egui_wgpu::RenderState`]
-    pub fn insert(wgpu_render_state: &egui_wgpu::RenderState, font_system: Arc<Mutex<FontSystem>>) {
+    /// Insert an instance of itself into the [`egui_wgpu::RenderState`].
+    ///
+    /// `rasterize_custom_glyph` is called once per [`glyphon::CustomGlyph`] found in a
+    /// [`BufferWithTextArea`] and must return its rasterized bitmap; pass `None` if the app
+    /// never attaches custom glyphs to its buffers.
+    ///
+    /// `config` controls the atlas's color mode, target format and multisampling; pass
+    /// [`GlyphonRendererConfig::default`] to reproduce the previous hardcoded behavior.
+    pub fn insert(
+        wgpu_render_state: &egui_wgpu::RenderState,
+        font_system: Arc<Mutex<FontSystem>>,
+        rasterize_custom_glyph: Option<
+            Box<dyn Fn(RasterizeCustomGlyphRequest) -> Option<RasterizedCustomGlyph> + Send + Sync>,
+        >,
+        config: GlyphonRendererConfig,
+    ) {
         let device = &wgpu_render_state.device;
         let queue = &wgpu_render_state.queue;
 
@@ -90,11 +159,12 @@ impl GlyphonRenderer {
             device,
             queue,
             &gcache,
-            wgpu_render_state.target_format,
-            ColorMode::Web,
+            config
+                .target_format
+                .unwrap_or(wgpu_render_state.target_format),
+            config.color_mode,
         );
-        let text_renderer =
-            TextRenderer::new(&mut atlas, device, wgpu::MultisampleState::default(), None);
+        let text_renderer = TextRenderer::new(&mut atlas, device, config.multisample, None);
 
         wgpu_render_state
             .renderer
@@ -106,9 +176,28 @@ impl GlyphonRenderer {
                 viewport,
                 atlas,
                 text_renderer,
+                multisample: config.multisample,
+                last_resolution: None,
+                callback_in_flight: AtomicBool::new(false),
+                has_prepared_state: AtomicBool::new(false),
+                rasterize_custom_glyph,
+                on_prepare_error: config.on_prepare_error,
             });
     }
 
+    /// Rebuild the internal [`glyphon::TextRenderer`] against a new [`wgpu::MultisampleState`].
+    ///
+    /// Call this whenever the egui surface's own multisample setting changes at runtime (e.g.
+    /// the user toggles MSAA); the sample count given here must keep matching the render pass
+    /// this renderer is used in or rendering into that pass will fail.
+    pub fn set_multisample(&mut self, device: &wgpu::Device, multisample: wgpu::MultisampleState) {
+        if self.multisample == multisample {
+            return;
+        }
+        self.text_renderer = TextRenderer::new(&mut self.atlas, device, multisample, None);
+        self.multisample = multisample;
+    }
+
     fn prepare<'a>(
         &mut self,
         device: &wgpu::Device,
@@ -116,8 +205,14 @@ impl GlyphonRenderer {
         screen_resolution: Resolution,
         text_areas: impl IntoIterator<Item = TextArea<'a>>,
     ) -> Result<(), PrepareError> {
-        self.viewport.update(queue, screen_resolution);
-        self.text_renderer.prepare(
+        // Several `GlyphonRendererCallback`s can share the one `Viewport` held here across
+        // frames at the same resolution; only re-upload the resolution when it actually changes.
+        if self.last_resolution != Some(screen_resolution) {
+            self.viewport.update(queue, screen_resolution);
+            self.last_resolution = Some(screen_resolution);
+        }
+        let rasterize_custom_glyph = &self.rasterize_custom_glyph;
+        self.text_renderer.prepare_with_custom(
             device,
             queue,
             self.font_system.lock().deref_mut(),
@@ -125,6 +220,7 @@ impl GlyphonRenderer {
             &self.viewport,
             text_areas,
             &mut self.cache,
+            |request| rasterize_custom_glyph.as_ref().and_then(|f| f(request)),
         )
     }
 
@@ -135,12 +231,27 @@ impl GlyphonRenderer {
 
 /// A callback which can be put into an [`egui_wgpu::renderer::Callback`].
 // And wrapped with an [`egui::PaintCallback`]. Only add one callback per individual
-// deffered viewport.
+// deffered viewport. The single [`GlyphonRenderer`] inserted into the render state can only
+// hold one callback's prepared glyphs at a time, so adding more than one per frame will panic;
+// use [`GlyphonRendererCallback::batched`] to combine buffers from several egui layers first.
 pub struct GlyphonRendererCallback {
     /// These buffers will be rendered to the screen all at the same time on the same layer.
     pub buffers: Vec<BufferWithTextArea>,
 }
 
+impl GlyphonRendererCallback {
+    /// Combine the buffers of several egui layers into a single callback, so they're prepared
+    /// and painted together against one shared [`Viewport`]. This is the supported way to draw
+    /// text from multiple layers in one frame; adding a separate [`GlyphonRendererCallback`]
+    /// per layer instead will panic, since the shared [`GlyphonRenderer`] can only hold one
+    /// callback's prepared glyphs at a time.
+    pub fn batched(layers: impl IntoIterator<Item = Vec<BufferWithTextArea>>) -> Self {
+        GlyphonRendererCallback {
+            buffers: layers.into_iter().flatten().collect(),
+        }
+    }
+}
+
 impl egui_wgpu::CallbackTrait for GlyphonRendererCallback {
     fn prepare(
         &self,
@@ -151,38 +262,69 @@ impl egui_wgpu::CallbackTrait for GlyphonRendererCallback {
         resources: &mut egui_wgpu::CallbackResources,
     ) -> Vec<wgpu::CommandBuffer> {
         let glyphon_renderer: &mut GlyphonRenderer = resources.get_mut().unwrap();
+        // `glyphon_renderer`'s single `text_renderer` can only hold one callback's prepared
+        // glyphs at a time, so a second callback's `prepare` before this one is painted would
+        // silently overwrite these glyphs instead of rendering them. Combine buffers from
+        // multiple egui layers with `GlyphonRendererCallback::batched` into one callback instead.
+        if glyphon_renderer
+            .callback_in_flight
+            .swap(true, Ordering::Relaxed)
+        {
+            panic!(
+                "GlyphonRendererCallback::prepare was called again before the previous \
+                 GlyphonRendererCallback was painted this frame. Only one GlyphonRendererCallback \
+                 may be added per frame; use GlyphonRendererCallback::batched to combine buffers \
+                 from multiple egui layers into a single callback instead."
+            );
+        }
+
         glyphon_renderer.atlas.trim();
         let bufrefs: Vec<_> = self.buffers.iter().map(|b| b.buffer.read()).collect();
-        let text_areas: Vec<_> = self
-            .buffers
-            .iter()
-            .enumerate()
-            .map(|(i, b)| TextArea {
-                custom_glyphs: &[],
-                buffer: bufrefs.get(i).unwrap(),
-                left: b.rect.left(),
-                top: b.rect.top(),
-                scale: b.scale,
-                bounds: TextBounds {
-                    left: b.rect.left() as i32,
-                    top: b.rect.top() as i32,
-                    right: b.rect.right() as i32,
-                    bottom: b.rect.bottom() as i32,
-                },
-                default_color: b.default_color,
-            })
-            .collect();
+        let build_text_areas = || -> Vec<TextArea> {
+            self.buffers
+                .iter()
+                .enumerate()
+                .map(|(i, b)| TextArea {
+                    custom_glyphs: &b.custom_glyphs,
+                    buffer: bufrefs.get(i).unwrap(),
+                    left: b.rect.left(),
+                    top: b.rect.top(),
+                    scale: b.scale,
+                    bounds: TextBounds {
+                        left: b.rect.left() as i32,
+                        top: b.rect.top() as i32,
+                        right: b.rect.right() as i32,
+                        bottom: b.rect.bottom() as i32,
+                    },
+                    default_color: b.default_color,
+                })
+                .collect()
+        };
+        let resolution = Resolution {
+            width: screen_descriptor.size_in_pixels[0],
+            height: screen_descriptor.size_in_pixels[1],
+        };
+
+        let mut result = glyphon_renderer.prepare(device, queue, resolution, build_text_areas());
+        // The atlas ran out of room for new glyphs (common when an app shows many distinct
+        // sizes/fonts at once). Trim unused entries and retry once before giving up on this
+        // frame's text instead of unwinding the whole render. This retry calls straight into
+        // `GlyphonRenderer::prepare`, not back into this method, so it can't trip the
+        // `callback_in_flight` guard above.
+        if let Err(PrepareError::AtlasFull) = result {
+            glyphon_renderer.atlas.trim();
+            result = glyphon_renderer.prepare(device, queue, resolution, build_text_areas());
+        }
+
+        let prepared = result.is_ok();
+        if let Err(err) = result {
+            if let Some(on_prepare_error) = &glyphon_renderer.on_prepare_error {
+                on_prepare_error(err);
+            }
+        }
         glyphon_renderer
-            .prepare(
-                device,
-                queue,
-                Resolution {
-                    width: screen_descriptor.size_in_pixels[0],
-                    height: screen_descriptor.size_in_pixels[1],
-                },
-                text_areas,
-            )
-            .unwrap();
+            .has_prepared_state
+            .store(prepared, Ordering::Relaxed);
         Vec::new()
     }
 
@@ -201,6 +343,14 @@ impl egui_wgpu::CallbackTrait for GlyphonRendererCallback {
             1.0,
         );
         let glyphon_renderer: &GlyphonRenderer = resources.get().unwrap();
-        glyphon_renderer.render(render_pass).unwrap();
+        glyphon_renderer
+            .callback_in_flight
+            .store(false, Ordering::Relaxed);
+        // Preparation may have failed (and kept failing through the atlas-full retry), in which
+        // case there's nothing valid to render this frame; skip it instead of unwrapping into a
+        // panic.
+        if glyphon_renderer.has_prepared_state.load(Ordering::Relaxed) {
+            glyphon_renderer.render(render_pass).unwrap();
+        }
     }
 }